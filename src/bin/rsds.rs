@@ -1,5 +1,7 @@
 use std::net::{Ipv4Addr, SocketAddr};
+use std::rc::Rc;
 use std::thread;
+use std::time::Duration;
 
 use futures::{FutureExt, StreamExt};
 use structopt::StructOpt;
@@ -7,11 +9,28 @@ use tokio::net::TcpListener;
 
 use rsds::comm::{observe_scheduler, CommRef};
 use rsds::core::CoreRef;
+use rsds::lifecycle::{BackgroundWorkers, ShutdownSignal};
+use rsds::metrics::{serve_metrics, ServerMetrics};
 use rsds::scheduler::comm::{prepare_scheduler_comm, SchedulerComm};
 use std::future::Future;
 use std::pin::Pin;
 use std::str::FromStr;
 
+/// Grace period for a drain before the process exits anyway.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Stops polling `fut` as soon as `shutdown` fires, without requiring `fut` itself
+/// to know about shutdown. Used for loops whose signature we don't control.
+async fn until_shutdown<F>(fut: F, mut shutdown: ShutdownSignal) -> rsds::Result<()>
+where
+    F: Future<Output = rsds::Result<()>>,
+{
+    futures::select! {
+        res = fut.fuse() => res,
+        _ = shutdown.wait().fuse() => Ok(()),
+    }
+}
+
 #[global_allocator]
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
@@ -51,6 +70,8 @@ struct Opt {
     port: u16,
     #[structopt(long, default_value = "workstealing")]
     scheduler: SchedulerType,
+    #[structopt(long, default_value = "9010")]
+    metrics_port: u16,
 }
 
 #[tokio::main(basic_scheduler)]
@@ -74,6 +95,7 @@ async fn main() -> rsds::Result<()> {
     let address = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), opt.port);
     log::info!("listening on port {}", address);
     let listener = TcpListener::bind(address).await?;
+    log::info!("metrics exposed on port {}", opt.metrics_port);
 
     let (comm, sender, receiver) = prepare_scheduler_comm();
 
@@ -93,19 +115,55 @@ async fn main() -> rsds::Result<()> {
         let core_ref = CoreRef::new();
         let core_ref2 = core_ref.clone();
         let comm_ref2 = comm_ref.clone();
+        let metrics = ServerMetrics::new();
+        let metrics_port = opt.metrics_port;
+        let lifecycle = Rc::new(BackgroundWorkers::new());
+        let metrics_signal = lifecycle.register("metrics-endpoint");
+        let scheduler_signal = lifecycle.register("scheduler");
+        let connection_signal = lifecycle.register("connection");
         task_set
             .run_until(async move {
-                let scheduler = observe_scheduler(core_ref2, comm_ref2, receiver);
-                let connection =
-                    rsds::comm::connection_initiator(listener, core_ref, comm_ref);
+                let scheduler = until_shutdown(
+                    observe_scheduler(core_ref2, comm_ref2, receiver),
+                    scheduler_signal,
+                );
+                let connection = until_shutdown(
+                    rsds::comm::connection_initiator(listener, core_ref, comm_ref),
+                    connection_signal,
+                );
+                let metrics_endpoint = serve_metrics(metrics.registry(), metrics_port, metrics_signal);
                 let end_flag = async move {
                     end_rx.next().await;
                     Ok(())
                 };
 
-                let futures = vec![scheduler.boxed_local(), connection.boxed_local(), end_flag.boxed_local()];
-                let (res, _, _) = futures::future::select_all(futures).await;
-                res
+                let futures = vec![
+                    scheduler.boxed_local(),
+                    connection.boxed_local(),
+                    metrics_endpoint.boxed_local(),
+                    end_flag.boxed_local(),
+                ];
+                let end_flag_index = futures.len() - 1;
+                let (res, index, remaining) = futures::future::select_all(futures).await;
+                if index != end_flag_index {
+                    // One of the long-lived loops itself gave up; nothing left to drain.
+                    return res;
+                }
+
+                log::info!("Shutdown requested, draining background workers");
+                lifecycle.shutdown();
+
+                let drain = futures::future::join_all(remaining);
+                futures::pin_mut!(drain);
+                let grace = tokio::time::delay_for(SHUTDOWN_GRACE_PERIOD);
+                futures::pin_mut!(grace);
+                futures::select! {
+                    _ = drain.fuse() => {},
+                    _ = grace.fuse() => {
+                        log::warn!("Shutdown grace period elapsed, forcing exit");
+                    }
+                }
+                Ok(())
             })
             .await
             .expect("Rsds failed");
@@ -0,0 +1,38 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum DsError {
+    GenericError(String),
+    IntegrityError(String),
+    SerializationError(String),
+}
+
+impl fmt::Display for DsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DsError::GenericError(msg) => write!(f, "{}", msg),
+            DsError::IntegrityError(msg) => write!(f, "{}", msg),
+            DsError::SerializationError(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for DsError {}
+
+impl From<std::io::Error> for DsError {
+    fn from(error: std::io::Error) -> Self {
+        DsError::GenericError(error.to_string())
+    }
+}
+
+impl From<rmp_serde::encode::Error> for DsError {
+    fn from(error: rmp_serde::encode::Error) -> Self {
+        DsError::SerializationError(error.to_string())
+    }
+}
+
+impl From<rmp_serde::decode::Error> for DsError {
+    fn from(error: rmp_serde::decode::Error) -> Self {
+        DsError::SerializationError(error.to_string())
+    }
+}
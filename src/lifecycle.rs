@@ -0,0 +1,56 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use tokio::stream::StreamExt;
+use tokio::sync::watch;
+
+#[derive(Clone)]
+pub struct ShutdownSignal {
+    receiver: watch::Receiver<bool>,
+}
+
+impl ShutdownSignal {
+    pub async fn wait(&mut self) {
+        while let Some(shutting_down) = self.receiver.next().await {
+            if shutting_down {
+                return;
+            }
+        }
+    }
+}
+
+/// Registry of a process's long-lived background loops, each with its own `ShutdownSignal`.
+pub struct BackgroundWorkers {
+    sender: watch::Sender<bool>,
+    receiver: watch::Receiver<bool>,
+    names: RefCell<Vec<&'static str>>,
+}
+
+impl BackgroundWorkers {
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        BackgroundWorkers {
+            sender,
+            receiver,
+            names: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn register(&self, name: &'static str) -> ShutdownSignal {
+        self.names.borrow_mut().push(name);
+        ShutdownSignal {
+            receiver: self.receiver.clone(),
+        }
+    }
+
+    /// Requests every registered background loop to begin winding down.
+    pub fn shutdown(&self) {
+        log::info!(
+            "Shutting down background workers: {:?}",
+            self.names.borrow()
+        );
+        let _ = self.sender.broadcast(true);
+    }
+}
+
+pub type BackgroundWorkersRef = Rc<BackgroundWorkers>;
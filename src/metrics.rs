@@ -0,0 +1,174 @@
+use std::net::{Ipv4Addr, SocketAddr};
+
+use futures::FutureExt;
+use prometheus::{Encoder, IntCounter, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+
+use crate::lifecycle::ShutdownSignal;
+
+pub struct WorkerMetrics {
+    registry: Registry,
+    pub ready_task_queue: IntGauge,
+    pub subworkers_free: IntGauge,
+    pub subworkers_busy: IntGauge,
+    pub tasks_by_state: IntGaugeVec,
+    pub data_objects_by_state: IntGaugeVec,
+    pub downloaded_bytes_total: IntCounter,
+    pub downloaded_objects_total: IntCounter,
+    pub tasks_finished_total: IntCounter,
+    pub tasks_stolen_total: IntCounter,
+}
+
+impl WorkerMetrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let ready_task_queue = IntGauge::new(
+            "rsds_worker_ready_task_queue",
+            "Number of tasks ready to run but not yet assigned to a subworker",
+        )
+        .unwrap();
+        let subworkers_free =
+            IntGauge::new("rsds_worker_subworkers_free", "Number of idle subworkers").unwrap();
+        let subworkers_busy = IntGauge::new(
+            "rsds_worker_subworkers_busy",
+            "Number of subworkers currently running a task",
+        )
+        .unwrap();
+        let tasks_by_state = IntGaugeVec::new(
+            Opts::new("rsds_worker_tasks", "Number of tasks by state"),
+            &["state"],
+        )
+        .unwrap();
+        let data_objects_by_state = IntGaugeVec::new(
+            Opts::new("rsds_worker_data_objects", "Number of data objects by state"),
+            &["state"],
+        )
+        .unwrap();
+        let downloaded_bytes_total = IntCounter::new(
+            "rsds_worker_downloaded_bytes_total",
+            "Cumulative bytes downloaded from other workers",
+        )
+        .unwrap();
+        let downloaded_objects_total = IntCounter::new(
+            "rsds_worker_downloaded_objects_total",
+            "Cumulative number of data objects downloaded from other workers",
+        )
+        .unwrap();
+        let tasks_finished_total = IntCounter::new(
+            "rsds_worker_tasks_finished_total",
+            "Cumulative number of tasks finished by this worker",
+        )
+        .unwrap();
+        let tasks_stolen_total = IntCounter::new(
+            "rsds_worker_tasks_stolen_total",
+            "Cumulative number of tasks stolen away from this worker",
+        )
+        .unwrap();
+
+        registry
+            .register(Box::new(ready_task_queue.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(subworkers_free.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(subworkers_busy.clone()))
+            .unwrap();
+        registry.register(Box::new(tasks_by_state.clone())).unwrap();
+        registry
+            .register(Box::new(data_objects_by_state.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(downloaded_bytes_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(downloaded_objects_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(tasks_finished_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(tasks_stolen_total.clone()))
+            .unwrap();
+
+        WorkerMetrics {
+            registry,
+            ready_task_queue,
+            subworkers_free,
+            subworkers_busy,
+            tasks_by_state,
+            data_objects_by_state,
+            downloaded_bytes_total,
+            downloaded_objects_total,
+            tasks_finished_total,
+            tasks_stolen_total,
+        }
+    }
+
+    pub fn registry(&self) -> Registry {
+        self.registry.clone()
+    }
+}
+
+/// Registry served by the server's metrics endpoint.
+///
+/// Deliberately empty: task-throughput and steal-count counters need a hook into
+/// scheduler/comm event handling to update, and those modules aren't part of this
+/// tree, so there's nothing here yet to wire real counters into. Add fields once
+/// that code exists rather than registering counters nothing ever increments.
+pub struct ServerMetrics {
+    registry: Registry,
+}
+
+impl ServerMetrics {
+    pub fn new() -> Self {
+        ServerMetrics {
+            registry: Registry::new(),
+        }
+    }
+
+    pub fn registry(&self) -> Registry {
+        self.registry.clone()
+    }
+}
+
+fn render(registry: &Registry) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    let encoder = TextEncoder::new();
+    encoder.encode(&registry.gather(), &mut buffer).unwrap();
+    buffer
+}
+
+/// Serves a Prometheus registry as plain text until `shutdown` fires.
+pub async fn serve_metrics(
+    registry: Registry,
+    port: u16,
+    mut shutdown: ShutdownSignal,
+) -> crate::Result<()> {
+    let address = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port);
+    let mut listener = TcpListener::bind(address).await?;
+    log::info!("Metrics endpoint listening on {}", address);
+    loop {
+        let (mut socket, _) = futures::select! {
+            accepted = listener.accept().fuse() => accepted?,
+            _ = shutdown.wait().fuse() => {
+                log::debug!("Metrics endpoint shutting down");
+                return Ok(());
+            }
+        };
+        let body = render(&registry);
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        );
+        if let Err(e) = socket.write_all(response.as_bytes()).await {
+            log::warn!("Failed to write metrics response: {}", e);
+            continue;
+        }
+        if let Err(e) = socket.write_all(&body).await {
+            log::warn!("Failed to write metrics body: {}", e);
+        }
+    }
+}
@@ -1,21 +1,28 @@
 use crate::common::data::SerializationType;
+use crate::common::Map;
 use crate::error::DsError::GenericError;
 use crate::scheduler::TaskId;
-use crate::transfer::messages::{DataRequest, DataResponse, FetchRequestMsg};
+use crate::server::worker::WorkerId;
+use crate::transfer::messages::{DataChunk, DataRequest, DataResponse, FetchRequestMsg};
 use bytes::BytesMut;
+use futures::future::Either;
 use futures::SinkExt;
+use std::future::Future;
 use tokio::net::TcpStream;
 use tokio::stream::StreamExt;
 use crate::error::DsError;
 
-pub async fn fetch_data(
-    mut stream: tokio_util::codec::Framed<TcpStream, tokio_util::codec::LengthDelimitedCodec>,
+async fn request_header(
+    address: &str,
     task_id: TaskId,
 ) -> crate::Result<(
     tokio_util::codec::Framed<TcpStream, tokio_util::codec::LengthDelimitedCodec>,
-    BytesMut,
-    SerializationType,
+    crate::transfer::messages::DataResponseHeader,
 )> {
+    let stream = TcpStream::connect(address).await?;
+    let mut stream =
+        tokio_util::codec::Framed::new(stream, tokio_util::codec::LengthDelimitedCodec::new());
+
     let message = DataRequest::FetchRequest(FetchRequestMsg { task_id });
     let data = rmp_serde::to_vec_named(&message)?;
     stream.send(data.into()).await?;
@@ -39,9 +46,217 @@ pub async fn fetch_data(
             return Err(DsError::GenericError("Request returned invalid response".into()));
         }
     };
-    let data = match stream.next().await {
-        None => return Err(GenericError("Unexpected close of connection".into())),
-        Some(data) => data?,
+    Ok((stream, header))
+}
+
+async fn fetch_data_from(
+    address: &str,
+    task_id: TaskId,
+) -> crate::Result<(
+    tokio_util::codec::Framed<TcpStream, tokio_util::codec::LengthDelimitedCodec>,
+    BytesMut,
+    SerializationType,
+)> {
+    let (stream, header) = request_header(address, task_id).await?;
+
+    let mut download = ChunkedDownload::new(stream, task_id);
+    let mut data = BytesMut::with_capacity(header.size as usize);
+    while let Some(chunk) = download.next_chunk().await? {
+        data.extend_from_slice(&chunk);
+    }
+    let stream = download.into_stream();
+
+    let data = match header.content_hash {
+        Some(expected) => verify_integrity(task_id, data, expected).await?,
+        None => data,
     };
     Ok((stream, data, header.serializer))
+}
+
+/// Pulls the chunked payload of a `DataResponse::Data` off the wire one frame at a time.
+pub struct ChunkedDownload {
+    stream: tokio_util::codec::Framed<TcpStream, tokio_util::codec::LengthDelimitedCodec>,
+    task_id: TaskId,
+    finished: bool,
+}
+
+impl ChunkedDownload {
+    pub fn new(
+        stream: tokio_util::codec::Framed<TcpStream, tokio_util::codec::LengthDelimitedCodec>,
+        task_id: TaskId,
+    ) -> Self {
+        ChunkedDownload {
+            stream,
+            task_id,
+            finished: false,
+        }
+    }
+
+    /// Returns the next chunk, or `None` once the end marker has been received.
+    pub async fn next_chunk(&mut self) -> crate::Result<Option<BytesMut>> {
+        if self.finished {
+            return Ok(None);
+        }
+        let frame = match self.stream.next().await {
+            None => return Err(GenericError("Unexpected close of connection".into())),
+            Some(data) => data?,
+        };
+        let chunk: DataChunk = rmp_serde::from_slice(&frame)?;
+        match chunk {
+            DataChunk::Chunk(data) => Ok(Some(BytesMut::from(&data[..]))),
+            DataChunk::End => {
+                self.finished = true;
+                log::debug!("Finished chunked download of data={}", self.task_id);
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn into_stream(
+        self,
+    ) -> tokio_util::codec::Framed<TcpStream, tokio_util::codec::LengthDelimitedCodec> {
+        self.stream
+    }
+}
+
+/// Hashes `data` on the blocking thread pool and compares it against `expected`.
+async fn verify_integrity(
+    task_id: TaskId,
+    data: BytesMut,
+    expected: [u8; 32],
+) -> crate::Result<BytesMut> {
+    let bytes = data.freeze();
+    let (bytes, digest) = tokio::task::spawn_blocking(move || {
+        let digest = *blake3::hash(&bytes).as_bytes();
+        (bytes, digest)
+    })
+    .await
+    .map_err(|e| DsError::GenericError(format!("Hashing task panicked: {}", e)))?;
+    if digest != expected {
+        log::error!("Fetching data={} failed: integrity check mismatch", task_id);
+        return Err(DsError::IntegrityError(format!(
+            "Content hash mismatch for data={}",
+            task_id
+        )));
+    }
+    Ok(bytes
+        .try_into_mut()
+        .unwrap_or_else(|bytes| BytesMut::from(&bytes[..])))
+}
+
+/// A response that fails to parse is a protocol bug, not a flaky replica.
+fn is_retryable(error: &DsError) -> bool {
+    !matches!(error, DsError::SerializationError(_))
+}
+
+/// Runs two fetches concurrently and returns the first to succeed.
+async fn race_for_success<T>(
+    first: impl Future<Output = crate::Result<T>>,
+    second: impl Future<Output = crate::Result<T>>,
+) -> crate::Result<T> {
+    match futures::future::select(Box::pin(first), Box::pin(second)).await {
+        Either::Left((Ok(v), _)) | Either::Right((Ok(v), _)) => Ok(v),
+        Either::Left((Err(_), other)) | Either::Right((Err(_), other)) => other.await,
+    }
+}
+
+/// Fetches `task_id` from the given replicas, retrying the next one on failure.
+/// Replicas are tried two at a time, in order; the two of each pair are raced concurrently.
+pub async fn fetch_data(
+    workers: &[WorkerId],
+    worker_addresses: &Map<WorkerId, String>,
+    task_id: TaskId,
+) -> crate::Result<(
+    tokio_util::codec::Framed<TcpStream, tokio_util::codec::LengthDelimitedCodec>,
+    BytesMut,
+    SerializationType,
+)> {
+    if workers.is_empty() {
+        return Err(GenericError("No replica holds the requested data".into()));
+    }
+
+    let mut last_error = None;
+    let mut remaining = workers.iter();
+
+    loop {
+        let first = match remaining.next() {
+            None => break,
+            Some(w) => w,
+        };
+        let address = match worker_addresses.get(first) {
+            None => continue,
+            Some(a) => a,
+        };
+        let second = remaining
+            .clone()
+            .next()
+            .and_then(|w| worker_addresses.get(w).map(|a| (w, a)));
+
+        let result = match second {
+            Some((_second_worker, second_address)) => {
+                // Consume the second candidate so it is not tried again on the next loop.
+                remaining.next();
+                race_for_success(
+                    fetch_data_from(address, task_id),
+                    fetch_data_from(second_address, task_id),
+                )
+                .await
+            }
+            None => fetch_data_from(address, task_id).await,
+        };
+
+        match result {
+            Ok(r) => return Ok(r),
+            Err(e) if is_retryable(&e) => {
+                log::warn!(
+                    "Fetching data={} from worker {} failed, trying next replica: {:?}",
+                    task_id,
+                    first,
+                    e
+                );
+                last_error = Some(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| GenericError("No replica holds the requested data".into())))
+}
+
+/// Like [`fetch_data`], but hands back a [`ChunkedDownload`] instead of an assembled
+/// `BytesMut`. Replicas are tried one at a time (none are raced), and the returned
+/// header's `content_hash` is not checked. Unused in this tree today.
+pub async fn fetch_data_chunked(
+    workers: &[WorkerId],
+    worker_addresses: &Map<WorkerId, String>,
+    task_id: TaskId,
+) -> crate::Result<(ChunkedDownload, crate::transfer::messages::DataResponseHeader)> {
+    if workers.is_empty() {
+        return Err(GenericError("No replica holds the requested data".into()));
+    }
+
+    let mut last_error = None;
+    for worker in workers {
+        let address = match worker_addresses.get(worker) {
+            None => continue,
+            Some(a) => a,
+        };
+        match request_header(address, task_id).await {
+            Ok((stream, header)) => {
+                return Ok((ChunkedDownload::new(stream, task_id), header));
+            }
+            Err(e) if is_retryable(&e) => {
+                log::warn!(
+                    "Fetching data={} from worker {} failed, trying next replica: {:?}",
+                    task_id,
+                    worker,
+                    e
+                );
+                last_error = Some(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| GenericError("No replica holds the requested data".into())))
 }
\ No newline at end of file
@@ -0,0 +1,47 @@
+use serde::{Deserialize, Serialize};
+
+use crate::common::data::SerializationType;
+use crate::scheduler::TaskId;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum DataRequest {
+    FetchRequest(FetchRequestMsg),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FetchRequestMsg {
+    pub task_id: TaskId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum DataResponse {
+    NotAvailable,
+    Data(DataResponseHeader),
+    DataUploaded(DataUploadedMsg),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DataResponseHeader {
+    pub serializer: SerializationType,
+    pub size: u64,
+
+    /// Digest computed by the producing worker, checked by the receiver.
+    #[serde(default)]
+    pub content_hash: Option<[u8; 32]>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DataUploadedMsg {
+    pub id: TaskId,
+}
+
+/// Max size of a single `DataChunk::Chunk` frame.
+pub const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// The producing side must send a `Chunk*`/`End` sequence after `DataResponse::Data`
+/// instead of one raw payload frame. Not implemented by any producer in this tree.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum DataChunk {
+    Chunk(Vec<u8>),
+    End,
+}
@@ -1,12 +1,17 @@
 use std::cmp::Reverse;
+use std::rc::Rc;
+use std::time::Duration;
 
 use bytes::{Bytes, BytesMut};
+use futures::FutureExt;
 use hashbrown::HashMap;
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 
 use crate::common::data::SerializationType;
 use crate::common::{Map, WrappedRcRefCell};
+use crate::metrics::WorkerMetrics;
 use crate::scheduler::TaskId;
+use crate::transfer::fetch::fetch_data;
 use crate::server::protocol::messages::worker::{
     DataDownloadedMsg, FromWorkerMessage, StealResponse,
 };
@@ -19,6 +24,60 @@ use crate::worker::task::{TaskRef, TaskState};
 
 pub type WorkerStateRef = WrappedRcRefCell<WorkerState>;
 
+fn data_object_state_label(state: &DataObjectState) -> &'static str {
+    match state {
+        DataObjectState::Remote(_) => "remote",
+        DataObjectState::Local(_) => "local",
+        DataObjectState::Removed => "removed",
+    }
+}
+
+/// Target duration for a single fetch; used to grow/shrink concurrency.
+const TARGET_FETCH_DURATION: Duration = Duration::from_millis(200);
+
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Additive-increase/multiplicative-decrease limiter for in-flight downloads.
+pub struct DownloadThrottle {
+    max_inflight: u32,
+    inflight: u32,
+    upper_bound: u32,
+}
+
+const DOWNLOAD_CONCURRENCY_FACTOR: u32 = 4;
+
+impl DownloadThrottle {
+    pub fn new(ncpus: u32) -> Self {
+        let upper_bound = (ncpus * DOWNLOAD_CONCURRENCY_FACTOR).max(1);
+        DownloadThrottle {
+            max_inflight: upper_bound.min(4),
+            inflight: 0,
+            upper_bound,
+        }
+    }
+
+    pub fn has_free_slot(&self) -> bool {
+        self.inflight < self.max_inflight
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.inflight == 0
+    }
+
+    pub fn on_download_started(&mut self) {
+        self.inflight += 1;
+    }
+
+    pub fn on_download_finished(&mut self, duration: Duration) {
+        self.inflight = self.inflight.saturating_sub(1);
+        if duration <= TARGET_FETCH_DURATION {
+            self.max_inflight = (self.max_inflight + 1).min(self.upper_bound);
+        } else {
+            self.max_inflight = (self.max_inflight / 2).max(1);
+        }
+    }
+}
+
 pub struct WorkerState {
     pub sender: UnboundedSender<Bytes>,
     pub ncpus: u32,
@@ -30,11 +89,25 @@ pub struct WorkerState {
         priority_queue::PriorityQueue<TaskRef, Reverse<(PriorityValue, PriorityValue)>>,
     pub data_objects: HashMap<TaskId, DataObjectRef>,
     pub download_sender: tokio::sync::mpsc::UnboundedSender<(DataObjectRef, Priority)>,
+    pub download_throttle: DownloadThrottle,
     pub worker_id: WorkerId,
     pub worker_addresses: Map<WorkerId, String>,
+    pub metrics: Rc<WorkerMetrics>,
+    shutting_down: bool,
 }
 
 impl WorkerState {
+    /// Stops handing out new tasks; running tasks are left to finish.
+    pub fn begin_shutdown(&mut self) {
+        log::info!("Worker draining: no new tasks will be started");
+        self.shutting_down = true;
+    }
+
+    /// Whether every subworker is idle and no download is in flight.
+    pub fn is_drained(&self) -> bool {
+        self.free_subworkers.len() == self.subworkers.len() && self.download_throttle.is_idle()
+    }
+
     pub fn set_subworkers(&mut self, subworkers: Vec<SubworkerRef>) {
         assert!(self.subworkers.is_empty() && self.free_subworkers.is_empty());
         self.free_subworkers = subworkers.clone();
@@ -45,6 +118,16 @@ impl WorkerState {
                 (id, s.clone())
             })
             .collect();
+        self.report_subworker_counts();
+    }
+
+    fn report_subworker_counts(&self) {
+        self.metrics
+            .subworkers_free
+            .set(self.free_subworkers.len() as i64);
+        self.metrics
+            .subworkers_busy
+            .set((self.subworkers.len() - self.free_subworkers.len()) as i64);
     }
 
     pub fn add_data_object(&mut self, data_ref: DataObjectRef) {
@@ -56,6 +139,19 @@ impl WorkerState {
         self.sender.send(data.into()).unwrap();
     }
 
+    /// Whether the download pump may start another download right now.
+    pub fn can_start_download(&self) -> bool {
+        self.download_throttle.has_free_slot()
+    }
+
+    pub fn on_download_started(&mut self) {
+        self.download_throttle.on_download_started();
+    }
+
+    pub fn on_download_finished(&mut self, duration: Duration) {
+        self.download_throttle.on_download_finished(duration);
+    }
+
     pub fn on_data_downloaded(
         &mut self,
         data_ref: DataObjectRef,
@@ -74,6 +170,17 @@ impl WorkerState {
                 }
                 DataObjectState::Local(_) => unreachable!(),
             }
+            self.metrics
+                .data_objects_by_state
+                .with_label_values(&["remote"])
+                .dec();
+            self.metrics
+                .data_objects_by_state
+                .with_label_values(&["local"])
+                .inc();
+            self.metrics.downloaded_objects_total.inc();
+            self.metrics.downloaded_bytes_total.inc_by(data.len() as u64);
+
             data_obj.state = DataObjectState::Local(LocalData {
                 serializer,
                 bytes: data.into(),
@@ -100,6 +207,9 @@ impl WorkerState {
     pub fn add_ready_task(&mut self, task_ref: TaskRef) {
         let priority = task_ref.get().priority.clone();
         self.ready_task_queue.push(task_ref, Reverse(priority));
+        self.metrics
+            .ready_task_queue
+            .set(self.ready_task_queue.len() as i64);
         self.try_start_tasks();
     }
 
@@ -121,6 +231,10 @@ impl WorkerState {
                 );
                 self.data_objects.insert(task_id, data_ref.clone());
                 is_remote = true;
+                self.metrics
+                    .data_objects_by_state
+                    .with_label_values(&["remote"])
+                    .inc();
                 data_ref
             }
             Some(data_ref) => {
@@ -150,6 +264,10 @@ impl WorkerState {
 
     pub fn add_task(&mut self, task_ref: TaskRef) {
         let id = task_ref.get().id;
+        self.metrics
+            .tasks_by_state
+            .with_label_values(&["waiting"])
+            .inc();
         if task_ref.get().is_ready() {
             log::debug!("Task {} is directly ready", id);
             self.add_ready_task(task_ref.clone());
@@ -165,19 +283,31 @@ impl WorkerState {
     }
 
     pub fn try_start_tasks(&mut self) {
-        if self.free_subworkers.is_empty() {
+        if self.shutting_down || self.free_subworkers.is_empty() {
             return;
         }
         while let Some((task_ref, _)) = self.ready_task_queue.pop() {
+            self.metrics
+                .ready_task_queue
+                .set(self.ready_task_queue.len() as i64);
             {
                 let subworker_ref = choose_subworker(self);
                 let mut task = task_ref.get_mut();
                 task.set_running(subworker_ref.clone());
+                self.metrics
+                    .tasks_by_state
+                    .with_label_values(&["waiting"])
+                    .dec();
+                self.metrics
+                    .tasks_by_state
+                    .with_label_values(&["running"])
+                    .inc();
                 let mut sw = subworker_ref.get_mut();
                 assert!(sw.running_task.is_none());
                 sw.running_task = Some(task_ref.clone());
                 sw.start_task(&task);
             }
+            self.report_subworker_counts();
             if self.free_subworkers.is_empty() {
                 return;
             }
@@ -186,8 +316,13 @@ impl WorkerState {
 
     pub fn remove_data(&mut self, task_id: TaskId) {
         log::info!("Removing data object {}", task_id);
+        let metrics = self.metrics.clone();
         self.data_objects.remove(&task_id).map(|data_ref| {
             let mut data_obj = data_ref.get_mut();
+            metrics
+                .data_objects_by_state
+                .with_label_values(&[data_object_state_label(&data_obj.state)])
+                .dec();
             data_obj.state = DataObjectState::Removed;
             if !data_obj.consumers.is_empty() {
                 todo!(); // What should happen when server removes data but there are tasks that needs it?
@@ -202,10 +337,22 @@ impl WorkerState {
                 assert!(!just_finished);
                 if x == 0 {
                     assert!(self.ready_task_queue.remove(&task_ref).is_some());
+                    self.metrics
+                        .ready_task_queue
+                        .set(self.ready_task_queue.len() as i64);
                 }
+                self.metrics
+                    .tasks_by_state
+                    .with_label_values(&["waiting"])
+                    .dec();
             }
             TaskState::Running(_) => {
                 assert!(just_finished);
+                self.metrics
+                    .tasks_by_state
+                    .with_label_values(&["running"])
+                    .dec();
+                self.metrics.tasks_finished_total.inc();
             }
             TaskState::Removed => {
                 unreachable!();
@@ -222,6 +369,10 @@ impl WorkerState {
                 match data.state {
                     DataObjectState::Remote(_) => {
                         assert!(!just_finished);
+                        self.metrics
+                            .data_objects_by_state
+                            .with_label_values(&["remote"])
+                            .dec();
                         data.state = DataObjectState::Removed;
                     }
                     DataObjectState::Local(_) => { /* Do nothing */ }
@@ -244,12 +395,49 @@ impl WorkerState {
                     }
                 }
                 self.remove_task(task_ref, false);
+                self.metrics.tasks_stolen_total.inc();
                 StealResponse::Ok
             }
         }
     }
 }
 
+type PendingDownloads = priority_queue::PriorityQueue<DataObjectRef, Reverse<PriorityValue>>;
+
+/// Starts as many queued downloads as the throttle currently allows, spawning each
+/// fetch on the local task set and reporting it back through `on_download_finished`
+/// and `on_data_downloaded` once it completes.
+fn try_start_downloads(state_ref: &WorkerStateRef, pending: &mut PendingDownloads) {
+    while state_ref.get().can_start_download() {
+        let data_ref = match pending.pop() {
+            Some((data_ref, _)) => data_ref,
+            None => return,
+        };
+        state_ref.get_mut().on_download_started();
+        let state_ref = state_ref.clone();
+        tokio::task::spawn_local(async move {
+            let (workers, worker_addresses, task_id) = {
+                let data_obj = data_ref.get();
+                let workers = match &data_obj.state {
+                    DataObjectState::Remote(remote) => remote.workers.clone(),
+                    _ => Vec::new(),
+                };
+                (workers, state_ref.get().worker_addresses.clone(), data_obj.id)
+            };
+            let start = std::time::Instant::now();
+            let result = fetch_data(&workers, &worker_addresses, task_id).await;
+            let mut state = state_ref.get_mut();
+            state.on_download_finished(start.elapsed());
+            match result {
+                Ok((_stream, data, serializer)) => {
+                    state.on_data_downloaded(data_ref, data, serializer)
+                }
+                Err(e) => log::error!("Download of data={} failed: {:?}", task_id, e),
+            }
+        });
+    }
+}
+
 impl WorkerStateRef {
     pub fn new(
         worker_id: WorkerId,
@@ -258,6 +446,7 @@ impl WorkerStateRef {
         listen_address: String,
         download_sender: tokio::sync::mpsc::UnboundedSender<(DataObjectRef, Priority)>,
         worker_addresses: Map<WorkerId, String>,
+        metrics: Rc<WorkerMetrics>,
     ) -> Self {
         Self::wrap(WorkerState {
             worker_id,
@@ -266,6 +455,9 @@ impl WorkerStateRef {
             ncpus,
             listen_address,
             download_sender,
+            download_throttle: DownloadThrottle::new(ncpus),
+            metrics,
+            shutting_down: false,
             tasks: Default::default(),
             subworkers: Default::default(),
             free_subworkers: Default::default(),
@@ -273,4 +465,88 @@ impl WorkerStateRef {
             data_objects: Default::default(),
         })
     }
+
+    /// Starts downloads off `download_queue` as the throttle allows, until `shutdown`
+    /// fires; then stops taking new tasks and waits (up to the grace period) for
+    /// running tasks and in-flight downloads to drain before returning.
+    pub async fn run(
+        &self,
+        mut download_queue: UnboundedReceiver<(DataObjectRef, Priority)>,
+        mut shutdown: crate::lifecycle::ShutdownSignal,
+    ) {
+        let mut pending = PendingDownloads::default();
+        loop {
+            futures::select! {
+                item = download_queue.recv().fuse() => match item {
+                    Some((data_ref, priority)) => {
+                        pending.push(data_ref, Reverse(priority));
+                        try_start_downloads(self, &mut pending);
+                    }
+                    None => break,
+                },
+                _ = shutdown.wait().fuse() => break,
+                _ = tokio::time::delay_for(Duration::from_millis(50)).fuse() => {
+                    // Refill slots freed by downloads that have finished since.
+                    try_start_downloads(self, &mut pending);
+                }
+            }
+        }
+
+        self.get_mut().begin_shutdown();
+        let deadline = tokio::time::Instant::now() + SHUTDOWN_GRACE_PERIOD;
+        while !self.get().is_drained() && tokio::time::Instant::now() < deadline {
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starts_at_four_and_caps_at_the_upper_bound() {
+        let mut throttle = DownloadThrottle::new(1);
+        assert_eq!(throttle.max_inflight, 4);
+        assert_eq!(throttle.upper_bound, 4);
+        throttle.on_download_started();
+        throttle.on_download_finished(Duration::from_millis(10));
+        assert_eq!(throttle.max_inflight, 4);
+    }
+
+    #[test]
+    fn grows_additively_on_a_fast_fetch() {
+        let mut throttle = DownloadThrottle::new(4); // upper_bound = 16, max_inflight = 4
+        throttle.on_download_started();
+        throttle.on_download_finished(Duration::from_millis(10));
+        assert_eq!(throttle.max_inflight, 5);
+    }
+
+    #[test]
+    fn halves_on_a_slow_fetch() {
+        let mut throttle = DownloadThrottle::new(4); // upper_bound = 16, max_inflight = 4
+        throttle.on_download_started();
+        throttle.on_download_finished(Duration::from_secs(1));
+        assert_eq!(throttle.max_inflight, 2);
+    }
+
+    #[test]
+    fn has_free_slot_respects_max_inflight() {
+        let mut throttle = DownloadThrottle::new(1); // max_inflight = 4
+        for _ in 0..4 {
+            assert!(throttle.has_free_slot());
+            throttle.on_download_started();
+        }
+        assert!(!throttle.has_free_slot());
+    }
+
+    #[test]
+    fn is_idle_tracks_inflight_count() {
+        let mut throttle = DownloadThrottle::new(1);
+        assert!(throttle.is_idle());
+        throttle.on_download_started();
+        assert!(!throttle.is_idle());
+        throttle.on_download_finished(Duration::from_millis(1));
+        assert!(throttle.is_idle());
+    }
 }